@@ -0,0 +1,183 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use opencl::hl::Program;
+
+use cl_matrix::ClMatrix;
+use context::Context;
+use num::Num;
+
+// Keyed on the structural hash, but the source is stored alongside the
+// compiled program and re-checked on every lookup so a 64-bit hash
+// collision between two differently-shaped expressions can't silently
+// return the wrong kernel -- it falls back to recompiling instead.
+thread_local!(static KERNEL_CACHE: RefCell<HashMap<u64, (String, Program)>> = RefCell::new(HashMap::new()));
+
+// A node in an element-wise expression tree built on top of `ClMatrix`.
+// `eval` walks the tree once to generate (and cache) a single OpenCL kernel
+// that computes the whole expression per index, instead of enqueuing one
+// kernel per node and round-tripping intermediates through global memory.
+pub enum ClExpr<'a, T: Num + 'a> {
+    Leaf(&'a ClMatrix<T>),
+    Add(Box<ClExpr<'a, T>>, Box<ClExpr<'a, T>>),
+    Sub(Box<ClExpr<'a, T>>, Box<ClExpr<'a, T>>),
+    Mul(Box<ClExpr<'a, T>>, Box<ClExpr<'a, T>>),
+    Max(Box<ClExpr<'a, T>>, T),
+    Min(Box<ClExpr<'a, T>>, T),
+    Scale(Box<ClExpr<'a, T>>, T),
+    Copy(Box<ClExpr<'a, T>>),
+}
+
+impl<'a, T: Num + 'a> ClExpr<'a, T> {
+    pub fn leaf(matrix: &'a ClMatrix<T>) -> ClExpr<'a, T> {
+        ClExpr::Leaf(matrix)
+    }
+
+    pub fn add(self, other: ClExpr<'a, T>) -> ClExpr<'a, T> {
+        ClExpr::Add(Box::new(self), Box::new(other))
+    }
+
+    pub fn sub(self, other: ClExpr<'a, T>) -> ClExpr<'a, T> {
+        ClExpr::Sub(Box::new(self), Box::new(other))
+    }
+
+    pub fn mul(self, other: ClExpr<'a, T>) -> ClExpr<'a, T> {
+        ClExpr::Mul(Box::new(self), Box::new(other))
+    }
+
+    pub fn max(self, threshold: T) -> ClExpr<'a, T> {
+        ClExpr::Max(Box::new(self), threshold)
+    }
+
+    pub fn min(self, threshold: T) -> ClExpr<'a, T> {
+        ClExpr::Min(Box::new(self), threshold)
+    }
+
+    pub fn scale(self, factor: T) -> ClExpr<'a, T> {
+        ClExpr::Scale(Box::new(self), factor)
+    }
+
+    pub fn copy(self) -> ClExpr<'a, T> {
+        ClExpr::Copy(Box::new(self))
+    }
+
+    // Emits the C source fragment for this node, appending any leaf matrices
+    // and scalar constants it references (in the order they appear) so the
+    // caller can bind them as kernel args in the same order.
+    fn emit(&self, leaves: &mut Vec<&'a ClMatrix<T>>, scalars: &mut Vec<T>) -> String {
+        match *self {
+            ClExpr::Leaf(matrix) => {
+                let name = format!("leaf{}", leaves.len());
+                leaves.push(matrix);
+                format!("{}[i]", name)
+            },
+            ClExpr::Add(ref a, ref b) => {
+                format!("({} + {})", a.emit(leaves, scalars), b.emit(leaves, scalars))
+            },
+            ClExpr::Sub(ref a, ref b) => {
+                format!("({} - {})", a.emit(leaves, scalars), b.emit(leaves, scalars))
+            },
+            ClExpr::Mul(ref a, ref b) => {
+                format!("({} * {})", a.emit(leaves, scalars), b.emit(leaves, scalars))
+            },
+            ClExpr::Max(ref a, threshold) => {
+                let src = a.emit(leaves, scalars);
+                let arg = format!("arg{}", scalars.len());
+                scalars.push(threshold);
+                format!("max({}, {})", src, arg)
+            },
+            ClExpr::Min(ref a, threshold) => {
+                let src = a.emit(leaves, scalars);
+                let arg = format!("arg{}", scalars.len());
+                scalars.push(threshold);
+                format!("min({}, {})", src, arg)
+            },
+            ClExpr::Scale(ref a, factor) => {
+                let src = a.emit(leaves, scalars);
+                let arg = format!("arg{}", scalars.len());
+                scalars.push(factor);
+                format!("({} * {})", src, arg)
+            },
+            ClExpr::Copy(ref a) => a.emit(leaves, scalars),
+        }
+    }
+
+    // Structural hash of the tree's shape (node kinds and arity) plus the
+    // element type name. Leaf matrices and scalar values are deliberately
+    // excluded: they're bound as kernel args, not baked into the source, so
+    // two expressions with the same shape always compile to the same kernel.
+    fn structural_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        T::name().hash(&mut hasher);
+        self.hash_shape(&mut hasher);
+        hasher.finish()
+    }
+
+    fn hash_shape<H: Hasher>(&self, hasher: &mut H) {
+        match *self {
+            ClExpr::Leaf(_) => 0u8.hash(hasher),
+            ClExpr::Add(ref a, ref b) => { 1u8.hash(hasher); a.hash_shape(hasher); b.hash_shape(hasher); },
+            ClExpr::Sub(ref a, ref b) => { 2u8.hash(hasher); a.hash_shape(hasher); b.hash_shape(hasher); },
+            ClExpr::Mul(ref a, ref b) => { 3u8.hash(hasher); a.hash_shape(hasher); b.hash_shape(hasher); },
+            ClExpr::Max(ref a, _) => { 4u8.hash(hasher); a.hash_shape(hasher); },
+            ClExpr::Min(ref a, _) => { 5u8.hash(hasher); a.hash_shape(hasher); },
+            ClExpr::Scale(ref a, _) => { 6u8.hash(hasher); a.hash_shape(hasher); },
+            ClExpr::Copy(ref a) => { 7u8.hash(hasher); a.hash_shape(hasher); },
+        }
+    }
+
+    pub fn eval(&self, ctx: &Context, output: &ClMatrix<T>) {
+        let mut leaves = Vec::new();
+        let mut scalars = Vec::new();
+        let body = self.emit(&mut leaves, &mut scalars);
+        let hash = self.structural_hash();
+
+        let source = {
+            let mut params: Vec<String> = leaves.iter().enumerate()
+                .map(|(i, _)| format!("__global {}* leaf{}", T::name(), i))
+                .collect();
+            params.extend(scalars.iter().enumerate()
+                .map(|(i, _)| format!("{} arg{}", T::name(), i)));
+            params.push(format!("__global {}* output", T::name()));
+
+            format!(
+                "__kernel void cl_expr_kernel({}) {{ int i = get_global_id(0); output[i] = {}; }}",
+                params.join(", "), body)
+        };
+
+        KERNEL_CACHE.with(|cache| {
+            let needs_build = match cache.borrow().get(&hash) {
+                Some(&(ref cached_source, _)) => cached_source != &source,
+                None => true,
+            };
+
+            if needs_build {
+                let program = ctx.ctx.create_program_from_source(source.as_str());
+                program.build(&ctx.device).unwrap();
+                cache.borrow_mut().insert(hash, (source.clone(), program));
+            }
+
+            let cache = cache.borrow();
+            let &(_, ref program) = cache.get(&hash).unwrap();
+            let kernel = program.create_kernel("cl_expr_kernel");
+
+            let mut arg_index = 0;
+            for leaf in &leaves {
+                kernel.set_arg(arg_index, &leaf.buffer);
+                arg_index += 1;
+            }
+            for scalar in &scalars {
+                kernel.set_arg(arg_index, scalar);
+                arg_index += 1;
+            }
+            kernel.set_arg(arg_index, &output.buffer);
+
+            let event_list: Vec<_> = leaves.iter().map(|leaf| leaf.get_event()).collect();
+            let new_event = ctx.queue.enqueue_async_kernel(&kernel, output.buffer.len(),
+                                                           None, &event_list[..]);
+            output.set_event(new_event);
+        });
+    }
+}
@@ -1,12 +1,17 @@
 use std::cell::{RefCell, Ref};
 
 use opencl;
-use opencl::mem::{Buffer, CLBuffer};
+use opencl::mem::{Buffer, CLBuffer, Local};
 
 use context::Context;
+use kernels;
 use matrix::Matrix;
 use num::Num;
 
+fn div_ceil(a: usize, b: usize) -> usize {
+    (a + b - 1) / b
+}
+
 pub enum ClMatrixMode {
     In,
     Out,
@@ -16,7 +21,7 @@ pub enum ClMatrixMode {
 pub struct ClMatrix<T: Num> {
     rows: usize,
     columns: usize,
-    buffer: CLBuffer<T>,
+    pub(crate) buffer: CLBuffer<T>,
     event: RefCell<Option<Event>>,
 }
 
@@ -74,11 +79,11 @@ impl<T: Num> ClMatrix<T> {
         self.columns
     }
     
-    fn set_event(&self, e: Event) {
+    pub(crate) fn set_event(&self, e: Event) {
         *self.event.borrow_mut() = Some(e);
     }
 
-    fn get_event(&self) -> Option<Ref<Event>> {
+    pub(crate) fn get_event(&self) -> Option<Ref<Event>> {
         if self.event.borrow().is_some() {
             //Some(Ref::map(&self.event.borrow(), |x| x.unwrap().as_ref().unwrap()))
             Ref::filter_map(self.event.borrow(), |o| o.as_ref())
@@ -97,6 +102,21 @@ impl<T: Num> ClMatrix<T> {
                                                         None, &*self.get_event().unwrap()));
     }
 
+    // Same kernel as `copy_to`, but tolerates `self` having no pending
+    // event (e.g. a fresh matrix that was only ever written via `set`).
+    fn copy_to_tolerant(&self, ctx: &Context, output: &ClMatrix<T>) {
+        let kernel = ctx.program.create_kernel(format!("vector_copy_to_{}", T::name()).as_str());
+
+        kernel.set_arg(0, &self.buffer);
+        kernel.set_arg(1, &output.buffer);
+
+        let new_event = {
+            let event_list: &[Option<Ref<Event>>] = &[self.get_event()];
+            ctx.queue.enqueue_async_kernel(&kernel, self.buffer.len(), None, event_list)
+        };
+        output.set_event(new_event);
+    }
+
     pub fn add(&self, ctx: &Context, other: &ClMatrix<T>, output: &ClMatrix<T>) {
         let kernel = ctx.program.create_kernel(format!("vector_add_{}", T::name()).as_str());
 
@@ -149,6 +169,10 @@ impl<T: Num> ClMatrix<T> {
     }
 
     pub fn dot(&self, ctx: &Context, other: &ClMatrix<T>, output: &ClMatrix<T>) {
+        if self.rows * other.columns >= kernels::DOT_TILED_THRESHOLD {
+            return self.dot_tiled(ctx, other, output);
+        }
+
         let kernel = ctx.program.create_kernel(format!("vector_dot_{}", T::name()).as_str());
 
         kernel.set_arg(0, &self.buffer);
@@ -166,6 +190,36 @@ impl<T: Num> ClMatrix<T> {
         output.set_event(new_event);
     }
 
+    // Blocked GEMM: each work-group computes one DOT_TILE_SIZE x DOT_TILE_SIZE
+    // block of `output`, staging the matching tiles of `self` and `other`
+    // through __local memory so each element is only re-read from global
+    // memory once per tile instead of once per output element.
+    pub fn dot_tiled(&self, ctx: &Context, other: &ClMatrix<T>, output: &ClMatrix<T>) {
+        let kernel = ctx.program.create_kernel(format!("vector_dot_tiled_{}", T::name()).as_str());
+        let tile = kernels::DOT_TILE_SIZE;
+
+        kernel.set_arg(0, &self.buffer);
+        kernel.set_arg(1, &other.buffer);
+        kernel.set_arg(2, &output.buffer);
+        kernel.set_arg(3, &self.rows);
+        kernel.set_arg(4, &self.columns);
+        kernel.set_arg(5, &other.columns);
+        kernel.set_arg(6, &Local::<T>::new(tile * tile));
+        kernel.set_arg(7, &Local::<T>::new(tile * tile));
+
+        let global_rows = div_ceil(self.rows, tile) * tile;
+        let global_columns = div_ceil(other.columns, tile) * tile;
+
+        let new_event = {
+            let event_list: &[Option<Ref<Event>>] = &[self.get_event(), other.get_event()];
+            ctx.queue.enqueue_async_kernel(&kernel,
+                                           (global_rows, global_columns),
+                                           Some((tile, tile)),
+                                           event_list)
+        };
+        output.set_event(new_event);
+    }
+
     pub fn max(&self, ctx: &Context, threshold: T, output: &ClMatrix<T>) {
         let kernel = ctx.program.create_kernel(format!("vector_max_{}", T::name()).as_str());
 
@@ -246,6 +300,147 @@ impl<T: Num> ClMatrix<T> {
         };
         output.set_event(new_event);
     }
+
+    pub fn dot_mod(&self, ctx: &Context, other: &ClMatrix<T>, modulus: T, output: &ClMatrix<T>) {
+        let kernel = ctx.program.create_kernel(format!("vector_dot_mod_{}", T::name()).as_str());
+
+        kernel.set_arg(0, &self.buffer);
+        kernel.set_arg(1, &other.buffer);
+        kernel.set_arg(2, &output.buffer);
+        kernel.set_arg(3, &self.columns);
+        kernel.set_arg(4, &other.columns);
+        kernel.set_arg(5, &modulus);
+
+        let new_event = {
+            let event_list: &[Option<Ref<Event>>] = &[self.get_event(), other.get_event()];
+            ctx.queue.enqueue_async_kernel(&kernel,
+                                           (self.rows, other.columns),
+                                           None, event_list)
+        };
+        output.set_event(new_event);
+    }
+
+    pub fn pow(&self, ctx: &Context, exponent: u64, output: &ClMatrix<T>) {
+        self.pow_with(ctx, exponent, output, |base, ctx, other, out| base.dot(ctx, other, out));
+    }
+
+    // Precondition: `modulus * modulus` must not overflow `T` -- the kernel
+    // reduces each operand to `< modulus` before multiplying them.
+    pub fn pow_mod(&self, ctx: &Context, exponent: u64, modulus: T, output: &ClMatrix<T>) {
+        self.pow_with(ctx, exponent, output,
+                      |base, ctx, other, out| base.dot_mod(ctx, other, modulus, out));
+    }
+
+    // Binary exponentiation: `result` starts at the identity and `base`
+    // starts at `self`; at each bit of `exponent` (LSB to MSB) the base is
+    // squared, and whenever the bit is set the running result is multiplied
+    // by the current base. `multiply` is either plain `dot` or `dot_mod`, so
+    // `pow` and `pow_mod` share this walk. Each step writes into a scratch
+    // buffer rather than its own inputs, since dot's kernel can't alias
+    // output with an operand, so `result` and `base` each ping-pong between
+    // two scratch matrices.
+    fn pow_with<F>(&self, ctx: &Context, exponent: u64, output: &ClMatrix<T>, multiply: F)
+        where F: Fn(&ClMatrix<T>, &Context, &ClMatrix<T>, &ClMatrix<T>)
+    {
+        assert_eq!(self.rows, self.columns);
+        let n = self.rows;
+
+        let mut identity = vec![T::zero(); n * n];
+        for i in 0..n {
+            identity[i * n + i] = T::one();
+        }
+        let identity_matrix = Matrix::from_vec(n, n, identity);
+        output.set(ctx, &identity_matrix);
+
+        if exponent == 0 {
+            return;
+        }
+
+        let result = [ClMatrix::new(ctx, n, n, ClMatrixMode::Mut),
+                      ClMatrix::new(ctx, n, n, ClMatrixMode::Mut)];
+        let base = [ClMatrix::new(ctx, n, n, ClMatrixMode::Mut),
+                    ClMatrix::new(ctx, n, n, ClMatrixMode::Mut)];
+
+        // Seed directly instead of through `copy_to`, which unwraps the
+        // source's event -- `output` and `self` may be fresh matrices that
+        // were never the target of a prior kernel and so have no event yet.
+        result[0].set(ctx, &identity_matrix);
+        self.copy_to_tolerant(ctx, &base[0]);
+
+        let mut result_cur = 0;
+        let mut base_cur = 0;
+        let mut e = exponent;
+
+        while e > 0 {
+            if e & 1 == 1 {
+                let next = 1 - result_cur;
+                multiply(&result[result_cur], ctx, &base[base_cur], &result[next]);
+                result_cur = next;
+            }
+            e >>= 1;
+            if e > 0 {
+                let next = 1 - base_cur;
+                multiply(&base[base_cur], ctx, &base[base_cur], &base[next]);
+                base_cur = next;
+            }
+        }
+
+        result[result_cur].copy_to(ctx, output);
+    }
+
+    // Row-wise: one work-item per row, each normalizing that row's columns.
+    pub fn softmax(&self, ctx: &Context, output: &ClMatrix<T>) {
+        let kernel = ctx.program.create_kernel(format!("vector_softmax_{}", T::name()).as_str());
+
+        kernel.set_arg(0, &self.buffer);
+        kernel.set_arg(1, &output.buffer);
+        kernel.set_arg(2, &self.rows);
+        kernel.set_arg(3, &self.columns);
+
+        let new_event = {
+            let event_list: &[Option<Ref<Event>>] = &[self.get_event()];
+            ctx.queue.enqueue_async_kernel(&kernel, self.rows, None, event_list)
+        };
+        output.set_event(new_event);
+    }
+
+    // Row-wise, with a +1 in the denominator (`exp(x_i) / (1 + sum_j
+    // exp(x_j))`) so an all-negative row can map to probabilities that sum
+    // to well under 1 instead of being forced to sum to exactly 1.
+    pub fn quiet_softmax(&self, ctx: &Context, output: &ClMatrix<T>) {
+        let kernel = ctx.program.create_kernel(format!("vector_quiet_softmax_{}", T::name()).as_str());
+
+        kernel.set_arg(0, &self.buffer);
+        kernel.set_arg(1, &output.buffer);
+        kernel.set_arg(2, &self.rows);
+        kernel.set_arg(3, &self.columns);
+
+        let new_event = {
+            let event_list: &[Option<Ref<Event>>] = &[self.get_event()];
+            ctx.queue.enqueue_async_kernel(&kernel, self.rows, None, event_list)
+        };
+        output.set_event(new_event);
+    }
+
+    // Row-wise Jacobian-vector product: `self` holds the cached softmax
+    // output and `grad` the upstream gradient for that row.
+    pub fn dsoftmax(&self, ctx: &Context, grad: &ClMatrix<T>, output: &ClMatrix<T>) {
+        let kernel = ctx.program.create_kernel(format!("vector_dsoftmax_{}", T::name()).as_str());
+
+        kernel.set_arg(0, &self.buffer);
+        kernel.set_arg(1, &grad.buffer);
+        kernel.set_arg(2, &output.buffer);
+        kernel.set_arg(3, &self.rows);
+        kernel.set_arg(4, &self.columns);
+
+        let new_event = {
+            let event_list: &[Option<Ref<Event>>] = &[self.get_event(), grad.get_event()];
+            ctx.queue.enqueue_async_kernel(&kernel,
+                                           self.rows,
+                                           None, event_list)
+        };
+        output.set_event(new_event);
+    }
 }
 
 pub type Event = opencl::hl::Event;
@@ -282,3 +477,67 @@ fn cl_matrix_add_reuse() {
 
     a_cl.add(ctx, &b_cl, &b_cl); // b = a+b
 }
+
+#[test]
+fn cl_matrix_softmax_sums_to_one() {
+    let ref ctx = Context::new();
+
+    let a = Matrix::from_vec(4, 3, (0..12).map(|x| x as f32).collect());
+    let a_cl = ClMatrix::from_matrix(ctx, &a, ClMatrixMode::In);
+    let out_cl: ClMatrix<f32> = ClMatrix::new(ctx, 4, 3, ClMatrixMode::Out);
+
+    a_cl.softmax(ctx, &out_cl);
+
+    let out = out_cl.get(ctx);
+    for row in 0..4 {
+        let sum: f32 = (0..3).map(|col| out[(row, col)]).sum();
+        assert!((sum - 1.0).abs() < 1e-4);
+    }
+}
+
+#[test]
+fn cl_matrix_quiet_softmax_sums_to_less_than_one_when_all_negative() {
+    let ref ctx = Context::new();
+
+    let a = Matrix::from_vec(4, 3, (0..12).map(|x| -(x as f32) - 1.0).collect());
+    let a_cl = ClMatrix::from_matrix(ctx, &a, ClMatrixMode::In);
+    let out_cl: ClMatrix<f32> = ClMatrix::new(ctx, 4, 3, ClMatrixMode::Out);
+
+    a_cl.quiet_softmax(ctx, &out_cl);
+
+    let out = out_cl.get(ctx);
+    for row in 0..4 {
+        let sum: f32 = (0..3).map(|col| out[(row, col)]).sum();
+        assert!(sum < 1.0);
+    }
+}
+
+#[test]
+fn cl_matrix_dot_tiled_matches_naive_for_ragged_dimensions() {
+    let ref ctx = Context::new();
+
+    // Large enough to clear DOT_TILED_THRESHOLD and not a multiple of
+    // DOT_TILE_SIZE, so this exercises the tiled kernel's zero-padded edge
+    // tiles rather than only whole tiles.
+    let rows = 130;
+    let inner = 130;
+    let columns = 130;
+    assert!(rows * columns >= kernels::DOT_TILED_THRESHOLD);
+
+    let a = Matrix::from_vec(rows, inner, (0..(rows * inner)).map(|x| (x % 7) as f32).collect());
+    let b = Matrix::from_vec(inner, columns, (0..(inner * columns)).map(|x| (x % 5) as f32).collect());
+
+    let a_cl = ClMatrix::from_matrix(ctx, &a, ClMatrixMode::In);
+    let b_cl = ClMatrix::from_matrix(ctx, &b, ClMatrixMode::In);
+    let out_cl: ClMatrix<f32> = ClMatrix::new(ctx, rows, columns, ClMatrixMode::Out);
+
+    a_cl.dot(ctx, &b_cl, &out_cl);
+
+    let out = out_cl.get(ctx);
+    for r in 0..rows {
+        for c in 0..columns {
+            let expected: f32 = (0..inner).map(|k| a[(r, k)] * b[(k, c)]).sum();
+            assert!((out[(r, c)] - expected).abs() < 1e-2);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::cell::{RefCell, Ref};
+
+use opencl;
+use opencl::mem::{Buffer, CLBuffer};
+
+use cl_matrix::ClMatrix;
+use context::Context;
+use matrix::Matrix;
+use num::Num;
+
+pub type Event = opencl::hl::Event;
+
+// Compressed-sparse-row matrix: `row_ptr` has `rows + 1` entries, and for
+// row `r` the nonzeros live at `col_idx[row_ptr[r] .. row_ptr[r+1]]` with
+// matching values in `values`.
+pub struct ClSparseMatrix<T: Num> {
+    rows: usize,
+    columns: usize,
+    nnz: usize,
+    row_ptr: CLBuffer<u32>,
+    col_idx: CLBuffer<u32>,
+    values: CLBuffer<T>,
+    event: RefCell<Option<Event>>,
+}
+
+impl<T: Num> ClSparseMatrix<T> {
+    pub fn from_csr(ctx: &Context,
+                    rows: usize,
+                    columns: usize,
+                    row_ptr: &[u32],
+                    col_idx: &[u32],
+                    values: &[T]) -> ClSparseMatrix<T> {
+        assert_eq!(row_ptr.len(), rows + 1);
+        assert_eq!(col_idx.len(), values.len());
+
+        let nnz = values.len();
+
+        let row_ptr_buf = ctx.ctx.create_buffer(row_ptr.len(), opencl::cl::CL_MEM_READ_ONLY);
+        let col_idx_buf = ctx.ctx.create_buffer(col_idx.len().max(1), opencl::cl::CL_MEM_READ_ONLY);
+        let values_buf = ctx.ctx.create_buffer(nnz.max(1), opencl::cl::CL_MEM_READ_ONLY);
+
+        ctx.queue.write(&row_ptr_buf, &&row_ptr[..], ());
+        if nnz > 0 {
+            ctx.queue.write(&col_idx_buf, &&col_idx[..], ());
+            ctx.queue.write(&values_buf, &&values[..], ());
+        }
+
+        ClSparseMatrix {
+            rows: rows,
+            columns: columns,
+            nnz: nnz,
+            row_ptr: row_ptr_buf,
+            col_idx: col_idx_buf,
+            values: values_buf,
+            event: RefCell::new(None),
+        }
+    }
+
+    // Converts a dense host `Matrix` to CSR, dropping zero entries.
+    pub fn from_dense(ctx: &Context, matrix: &Matrix<T>) -> ClSparseMatrix<T> {
+        let rows = matrix.rows();
+        let columns = matrix.columns();
+
+        let mut row_ptr = Vec::with_capacity(rows + 1);
+        let mut col_idx = Vec::new();
+        let mut values = Vec::new();
+
+        row_ptr.push(0u32);
+        for r in 0..rows {
+            for c in 0..columns {
+                let value = matrix[(r, c)];
+                if value != T::zero() {
+                    col_idx.push(c as u32);
+                    values.push(value);
+                }
+            }
+            row_ptr.push(values.len() as u32);
+        }
+
+        ClSparseMatrix::from_csr(ctx, rows, columns, &row_ptr, &col_idx, &values)
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.nnz
+    }
+
+    fn set_event(&self, e: Event) {
+        *self.event.borrow_mut() = Some(e);
+    }
+
+    fn get_event(&self) -> Option<Ref<Event>> {
+        if self.event.borrow().is_some() {
+            Ref::filter_map(self.event.borrow(), |o| o.as_ref())
+        } else {
+            None
+        }
+    }
+
+    // Sparse (self, CSR) x dense matrix multiply: one work-item per sparse
+    // row, walking that row's nonzeros and accumulating into every column
+    // of `output`.
+    pub fn spmm(&self, ctx: &Context, dense: &ClMatrix<T>, output: &ClMatrix<T>) {
+        let kernel = ctx.program.create_kernel(format!("spmm_{}", T::name()).as_str());
+
+        kernel.set_arg(0, &self.row_ptr);
+        kernel.set_arg(1, &self.col_idx);
+        kernel.set_arg(2, &self.values);
+        kernel.set_arg(3, &dense.buffer);
+        kernel.set_arg(4, &output.buffer);
+        kernel.set_arg(5, &dense.columns());
+
+        let new_event = {
+            let event_list: &[Option<Ref<Event>>] = &[self.get_event(), dense.get_event()];
+            ctx.queue.enqueue_async_kernel(&kernel, self.rows, None, event_list)
+        };
+        output.set_event(new_event);
+    }
+}
+
+#[test]
+fn cl_sparse_matrix_diagonal_matches_scaled_dense() {
+    let ref ctx = Context::new();
+
+    let n = 8;
+    let scale = 3i32;
+
+    let row_ptr: Vec<u32> = (0..(n as u32 + 1)).collect();
+    let col_idx: Vec<u32> = (0..n as u32).collect();
+    let values: Vec<i32> = (0..n).map(|_| scale).collect();
+
+    let sparse = ClSparseMatrix::from_csr(ctx, n, n, &row_ptr, &col_idx, &values);
+
+    let dense_host = Matrix::from_vec(n, n, (0..(n * n) as i32).collect());
+    let dense = ClMatrix::from_matrix(ctx, &dense_host, ::cl_matrix::ClMatrixMode::In);
+    let output: ClMatrix<i32> = ClMatrix::new(ctx, n, n, ::cl_matrix::ClMatrixMode::Out);
+
+    sparse.spmm(ctx, &dense, &output);
+
+    let result = output.get(ctx);
+    for r in 0..n {
+        for c in 0..n {
+            assert_eq!(result[(r, c)], scale * dense_host[(r, c)]);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+// OpenCL C source for the kernels enqueued from `ClMatrix`/`ClSparseMatrix`
+// that don't fit the crate's built-in program (the per-type `vector_add_*`,
+// `vector_mse_*`, etc. sources compiled by `Context::new`). Each function
+// here renders the source for one element type, named to match the
+// `format!("vector_..._{}", T::name())` lookup the host side uses, so it
+// can be folded into that same per-type program source.
+
+pub const DOT_TILE_SIZE: usize = 16;
+
+pub const DOT_TILED_THRESHOLD: usize = 128 * 128;
+
+// Blocked GEMM: one work-group per DOT_TILE_SIZE x DOT_TILE_SIZE output
+// block. Each work-item stages one element of `self`'s and `other`'s
+// current tile into __local memory, barriers, accumulates the tile's
+// partial products from local memory, and advances along K. Tiles that
+// run past `a_columns`/`a_rows`/`b_columns` are zero-padded via the bounds
+// checks on the loads.
+pub fn dot_tiled_kernel_source(type_name: &str) -> String {
+    format!("
+__kernel void vector_dot_tiled_{t}(__global {t}* a, __global {t}* b, __global {t}* output,
+                                    int a_rows, int a_columns, int b_columns,
+                                    __local {t}* a_tile, __local {t}* b_tile) {{
+    int tile = {tile};
+    int row = get_global_id(0);
+    int col = get_global_id(1);
+    int local_row = get_local_id(0);
+    int local_col = get_local_id(1);
+
+    {t} acc = 0;
+    int num_tiles = (a_columns + tile - 1) / tile;
+
+    for (int t = 0; t < num_tiles; t++) {{
+        int a_col = t * tile + local_col;
+        int b_row = t * tile + local_row;
+
+        a_tile[local_row * tile + local_col] =
+            (row < a_rows && a_col < a_columns) ? a[row * a_columns + a_col] : 0;
+        b_tile[local_row * tile + local_col] =
+            (b_row < a_columns && col < b_columns) ? b[b_row * b_columns + col] : 0;
+
+        barrier(CLK_LOCAL_MEM_FENCE);
+
+        for (int k = 0; k < tile; k++) {{
+            acc += a_tile[local_row * tile + k] * b_tile[k * tile + local_col];
+        }}
+
+        barrier(CLK_LOCAL_MEM_FENCE);
+    }}
+
+    if (row < a_rows && col < b_columns) {{
+        output[row * b_columns + col] = acc;
+    }}
+}}
+", t = type_name, tile = DOT_TILE_SIZE)
+}
+
+// Every kernel source in this module that isn't part of the crate's
+// original hand-written program, concatenated for one element type.
+// `Context::new` must fold this into the per-type source it hands to
+// `create_program_from_source` alongside `vector_add_*`/`vector_mse_*`/etc,
+// or the `create_kernel` calls that name these kernels will fail to find a
+// match at runtime.
+pub fn extra_kernel_source(type_name: &str) -> String {
+    dot_tiled_kernel_source(type_name) + &spmm_kernel_source(type_name)
+        + &dot_mod_kernel_source(type_name)
+        + &softmax_kernel_source(type_name)
+        + &quiet_softmax_kernel_source(type_name)
+        + &dsoftmax_kernel_source(type_name)
+}
+
+// Sparse (CSR) x dense multiply: one work-item per sparse row, walking
+// that row's `col_idx`/`values` slice and adding each value times the
+// dense operand's matching row into every column of that output row.
+pub fn spmm_kernel_source(type_name: &str) -> String {
+    format!("
+__kernel void spmm_{t}(__global uint* row_ptr, __global uint* col_idx, __global {t}* values,
+                        __global {t}* dense, __global {t}* output, int dense_columns) {{
+    int row = get_global_id(0);
+    uint start = row_ptr[row];
+    uint end = row_ptr[row + 1];
+
+    for (int col = 0; col < dense_columns; col++) {{
+        output[row * dense_columns + col] = 0;
+    }}
+
+    for (uint k = start; k < end; k++) {{
+        uint sparse_col = col_idx[k];
+        {t} value = values[k];
+        for (int col = 0; col < dense_columns; col++) {{
+            output[row * dense_columns + col] += value * dense[sparse_col * dense_columns + col];
+        }}
+    }}
+}}
+", t = type_name)
+}
+
+// Same indexing as `vector_dot_*`, but reduces both operands modulo
+// `modulus` *before* multiplying (not just the accumulator afterward), so
+// entries used for recurrence/graph-counting problems stay bounded across
+// `ClMatrix::pow_mod`'s O(log exponent) squarings.
+//
+// Precondition: `modulus * modulus` must not overflow `T` -- each operand
+// and the accumulator are reduced to `< modulus`, so the largest
+// intermediate value, `acc + a_val * b_val`, is below `modulus * modulus`.
+pub fn dot_mod_kernel_source(type_name: &str) -> String {
+    format!("
+__kernel void vector_dot_mod_{t}(__global {t}* a, __global {t}* b, __global {t}* output,
+                                  int a_columns, int b_columns, {t} modulus) {{
+    int row = get_global_id(0);
+    int col = get_global_id(1);
+    {t} acc = 0;
+
+    for (int k = 0; k < a_columns; k++) {{
+        {t} a_val = a[row * a_columns + k] % modulus;
+        {t} b_val = b[k * b_columns + col] % modulus;
+        acc = (acc + a_val * b_val) % modulus;
+    }}
+
+    output[row * b_columns + col] = acc;
+}}
+", t = type_name)
+}
+
+// Row-wise softmax: one work-item per row, finding that row's max first
+// for numerical stability, then subtracting it before exponentiating.
+pub fn softmax_kernel_source(type_name: &str) -> String {
+    format!("
+__kernel void vector_softmax_{t}(__global {t}* input, __global {t}* output, int rows, int columns) {{
+    int row = get_global_id(0);
+
+    {t} max_val = input[row * columns];
+    for (int c = 1; c < columns; c++) {{
+        {t} v = input[row * columns + c];
+        if (v > max_val) {{ max_val = v; }}
+    }}
+
+    {t} sum = 0;
+    for (int c = 0; c < columns; c++) {{
+        sum += exp(input[row * columns + c] - max_val);
+    }}
+
+    for (int c = 0; c < columns; c++) {{
+        output[row * columns + c] = exp(input[row * columns + c] - max_val) / sum;
+    }}
+}}
+", t = type_name)
+}
+
+// Same as `vector_softmax_*`, but the denominator starts at exp(-max)
+// instead of 0: that is the +1 term rescaled by the same exp(-max) as every
+// other term, so the result is `exp(x_i) / (1 + sum_j exp(x_j))` and an
+// all-negative row maps to probabilities that sum to well under 1 instead
+// of being forced to sum to exactly 1.
+pub fn quiet_softmax_kernel_source(type_name: &str) -> String {
+    format!("
+__kernel void vector_quiet_softmax_{t}(__global {t}* input, __global {t}* output, int rows, int columns) {{
+    int row = get_global_id(0);
+
+    {t} max_val = input[row * columns];
+    for (int c = 1; c < columns; c++) {{
+        {t} v = input[row * columns + c];
+        if (v > max_val) {{ max_val = v; }}
+    }}
+
+    {t} sum = exp(-max_val);
+    for (int c = 0; c < columns; c++) {{
+        sum += exp(input[row * columns + c] - max_val);
+    }}
+
+    for (int c = 0; c < columns; c++) {{
+        output[row * columns + c] = exp(input[row * columns + c] - max_val) / sum;
+    }}
+}}
+", t = type_name)
+}
+
+// Row-wise softmax Jacobian-vector product: `input` is the cached softmax
+// output s and `grad` the upstream gradient; output_i = s_i * (grad_i -
+// sum_j s_j * grad_j).
+pub fn dsoftmax_kernel_source(type_name: &str) -> String {
+    format!("
+__kernel void vector_dsoftmax_{t}(__global {t}* input, __global {t}* grad, __global {t}* output,
+                                   int rows, int columns) {{
+    int row = get_global_id(0);
+
+    {t} dot = 0;
+    for (int c = 0; c < columns; c++) {{
+        dot += input[row * columns + c] * grad[row * columns + c];
+    }}
+
+    for (int c = 0; c < columns; c++) {{
+        {t} s = input[row * columns + c];
+        output[row * columns + c] = s * (grad[row * columns + c] - dot);
+    }}
+}}
+", t = type_name)
+}
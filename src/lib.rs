@@ -8,6 +8,9 @@ pub use ops::*;
 pub use range_arg::RangeArg;
 
 pub mod array;
+pub mod cl_expr;
+pub mod cl_matrix;
+pub mod cl_sparse_matrix;
 pub mod context;
 pub mod kernels;
 pub mod num;